@@ -1,3 +1,11 @@
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    ops::Range,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
 pub type Snippet<'a> = Vec<SnippetElement<'a>>;
 
 #[derive(Debug, PartialEq, Eq)]
@@ -9,7 +17,7 @@ pub enum CaseChange {
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum FormatItem<'a> {
-    Text(&'a str),
+    Text(Cow<'a, str>),
     Capture(usize),
     CaseChange(usize, CaseChange),
     Conditional(usize, Option<&'a str>, Option<&'a str>),
@@ -29,23 +37,512 @@ pub enum SnippetElement<'a> {
     },
     Placeholder {
         tabstop: usize,
-        value: Box<SnippetElement<'a>>,
+        value: Snippet<'a>,
     },
     Choice {
         tabstop: usize,
-        choices: Vec<&'a str>,
+        choices: Vec<Cow<'a, str>>,
     },
     Variable {
         name: &'a str,
         default: Option<&'a str>,
         regex: Option<Regex<'a>>,
     },
-    Text(&'a str),
+    Text(Cow<'a, str>),
+}
+
+/// The result of [`render`]ing a [`Snippet`]: the literal text to insert and
+/// the tabstops within that text that the editor should cycle the cursor
+/// through.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RenderedSnippet {
+    pub text: String,
+    /// Tabstops in visit order: `$1`, `$2`, … and finally `$0`.
+    pub tabstops: Vec<Tabstop>,
+}
+
+/// A tabstop within a [`RenderedSnippet`]. Snippets may repeat a tabstop
+/// index (for example `${1:foo} ${1:foo}`); every occurrence is recorded
+/// here as a "mirror" so the editor can keep them in sync as the user types.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Tabstop {
+    pub tabstop: usize,
+    /// Byte ranges into `RenderedSnippet::text`, one per mirror.
+    pub ranges: Vec<Range<usize>>,
+    /// The alternatives offered by a `${n|one,two,three|}` choice. Empty
+    /// unless this tabstop came from a [`SnippetElement::Choice`].
+    pub choices: Vec<String>,
+}
+
+#[derive(Default)]
+struct RenderState {
+    text: String,
+    tabstops: HashMap<usize, (Vec<Range<usize>>, Vec<String>)>,
+}
+
+impl RenderState {
+    fn push_element(&mut self, element: &SnippetElement, ctx: &SnippetRenderCtx, now: SystemTime) {
+        match element {
+            SnippetElement::Tabstop { tabstop } => {
+                let start = self.text.len();
+                self.tabstops
+                    .entry(*tabstop)
+                    .or_default()
+                    .0
+                    .push(start..start);
+            }
+            SnippetElement::Placeholder { tabstop, value } => {
+                let start = self.text.len();
+                for element in value {
+                    self.push_element(element, ctx, now);
+                }
+                let end = self.text.len();
+                self.tabstops
+                    .entry(*tabstop)
+                    .or_default()
+                    .0
+                    .push(start..end);
+            }
+            SnippetElement::Choice { tabstop, choices } => {
+                let start = self.text.len();
+                if let Some(first) = choices.first() {
+                    self.text.push_str(first);
+                }
+                let end = self.text.len();
+                let entry = self.tabstops.entry(*tabstop).or_default();
+                entry.0.push(start..end);
+                entry.1 = choices.iter().map(|choice| choice.to_string()).collect();
+            }
+            SnippetElement::Variable {
+                name,
+                default,
+                regex,
+            } => {
+                let value = resolve_variable(name, *default, regex.as_ref(), ctx, now);
+                self.text.push_str(&value);
+            }
+            SnippetElement::Text(text) => self.text.push_str(text),
+        }
+    }
+}
+
+/// The cursor's 0-indexed line and column, used to derive `TM_LINE_NUMBER`
+/// and `TM_LINE_INDEX`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CursorPosition {
+    pub line: usize,
+    pub column: usize,
+}
+
+/// Editor state a [`Snippet`] is rendered against. Fields here are the
+/// source of truth for the LSP built-in variables (`TM_FILENAME`,
+/// `TM_SELECTED_TEXT`, `CLIPBOARD`, `TM_LINE_NUMBER`, `CURRENT_YEAR`, …);
+/// `resolve_variable` derives a variable's value from them before falling
+/// back to its `default` text, and then to its name rendered as a literal.
+/// `variables` is an escape hatch, checked first, for anything the fields
+/// below don't cover (or to override a derived value, e.g. in tests).
+#[derive(Debug, Default, Clone)]
+pub struct SnippetRenderCtx {
+    /// Path of the file the snippet is being inserted into; feeds
+    /// `TM_FILENAME`, `TM_FILENAME_BASE`, `TM_DIRECTORY` and `TM_FILEPATH`.
+    pub filename: Option<PathBuf>,
+    /// Currently selected text, if any; feeds `TM_SELECTED_TEXT`.
+    pub selection: Option<String>,
+    /// System clipboard contents, if any; feeds `CLIPBOARD`.
+    pub clipboard: Option<String>,
+    /// Cursor position at the time of insertion; feeds `TM_LINE_NUMBER` and
+    /// `TM_LINE_INDEX`.
+    pub cursor: Option<CursorPosition>,
+    /// Clock to derive the `CURRENT_*` date/time variables from. Defaults to
+    /// [`SystemTime::now`] when unset; set this explicitly to pin the clock
+    /// in tests. Dates are computed in UTC.
+    pub now: Option<SystemTime>,
+    pub variables: HashMap<String, String>,
+}
+
+/// Resolves the LSP built-in variables that [`SnippetRenderCtx`]'s typed
+/// fields describe. `now` is the instant to derive the `CURRENT_*` variables
+/// from, resolved once per [`render`] call so every variable in a snippet
+/// sees the same clock reading. Returns `None` for any name it doesn't
+/// recognize, so the caller can fall back to `default`/`name`.
+fn derive_variable(name: &str, ctx: &SnippetRenderCtx, now: SystemTime) -> Option<String> {
+    match name {
+        "TM_SELECTED_TEXT" => ctx.selection.clone(),
+        "CLIPBOARD" => ctx.clipboard.clone(),
+        "TM_FILENAME" => ctx
+            .filename
+            .as_ref()
+            .and_then(|path| path.file_name())
+            .map(|name| name.to_string_lossy().into_owned()),
+        "TM_FILENAME_BASE" => ctx
+            .filename
+            .as_ref()
+            .and_then(|path| path.file_stem())
+            .map(|stem| stem.to_string_lossy().into_owned()),
+        "TM_DIRECTORY" => ctx
+            .filename
+            .as_ref()
+            .and_then(|path| path.parent())
+            .map(|dir| dir.to_string_lossy().into_owned()),
+        "TM_FILEPATH" => ctx
+            .filename
+            .as_ref()
+            .map(|path| path.to_string_lossy().into_owned()),
+        "TM_LINE_NUMBER" => ctx.cursor.map(|cursor| (cursor.line + 1).to_string()),
+        "TM_LINE_INDEX" => ctx.cursor.map(|cursor| cursor.line.to_string()),
+        "CURRENT_YEAR" | "CURRENT_YEAR_SHORT" | "CURRENT_MONTH" | "CURRENT_MONTH_NAME"
+        | "CURRENT_MONTH_NAME_SHORT" | "CURRENT_DATE" | "CURRENT_DAY_NAME"
+        | "CURRENT_DAY_NAME_SHORT" | "CURRENT_HOUR" | "CURRENT_MINUTE" | "CURRENT_SECOND" => {
+            Some(format_current_datetime(name, now))
+        }
+        _ => None,
+    }
+}
+
+const MONTH_NAMES: [&str; 12] = [
+    "January",
+    "February",
+    "March",
+    "April",
+    "May",
+    "June",
+    "July",
+    "August",
+    "September",
+    "October",
+    "November",
+    "December",
+];
+const DAY_NAMES: [&str; 7] = [
+    "Sunday",
+    "Monday",
+    "Tuesday",
+    "Wednesday",
+    "Thursday",
+    "Friday",
+    "Saturday",
+];
+
+fn format_current_datetime(name: &str, now: SystemTime) -> String {
+    let (year, month, day, hour, minute, second, weekday) = civil_datetime(now);
+    match name {
+        "CURRENT_YEAR" => year.to_string(),
+        "CURRENT_YEAR_SHORT" => format!("{:02}", year % 100),
+        "CURRENT_MONTH" => format!("{month:02}"),
+        "CURRENT_MONTH_NAME" => MONTH_NAMES[(month - 1) as usize].to_string(),
+        "CURRENT_MONTH_NAME_SHORT" => MONTH_NAMES[(month - 1) as usize][..3].to_string(),
+        "CURRENT_DATE" => format!("{day:02}"),
+        "CURRENT_DAY_NAME" => DAY_NAMES[weekday as usize].to_string(),
+        "CURRENT_DAY_NAME_SHORT" => DAY_NAMES[weekday as usize][..3].to_string(),
+        "CURRENT_HOUR" => format!("{hour:02}"),
+        "CURRENT_MINUTE" => format!("{minute:02}"),
+        "CURRENT_SECOND" => format!("{second:02}"),
+        _ => unreachable!("only called for the CURRENT_* names matched in derive_variable"),
+    }
+}
+
+/// Splits `time` into UTC (year, month, day, hour, minute, second, weekday)
+/// components, where weekday is 0 (Sunday) through 6 (Saturday). Avoids
+/// pulling in a calendar crate for just this: the date math is Howard
+/// Hinnant's `civil_from_days`, and 1970-01-01 (day 0) is known to be a
+/// Thursday.
+fn civil_datetime(time: SystemTime) -> (i64, u32, u32, u32, u32, u32, u32) {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_secs() as i64)
+        .unwrap_or(0);
+    let days = secs.div_euclid(86400);
+    let time_of_day = secs.rem_euclid(86400);
+
+    let z = days + 719468;
+    let era = if z >= 0 { z } else { z - 146096 } / 146097;
+    let day_of_era = (z - era * 146097) as u64;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096)
+        / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era as i64 + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    let hour = (time_of_day / 3600) as u32;
+    let minute = ((time_of_day % 3600) / 60) as u32;
+    let second = (time_of_day % 60) as u32;
+    let weekday = (days.rem_euclid(7) + 4).rem_euclid(7) as u32;
+
+    (year, month, day, hour, minute, second, weekday)
+}
+
+fn resolve_variable<'a>(
+    name: &'a str,
+    default: Option<&'a str>,
+    regex: Option<&Regex<'a>>,
+    ctx: &SnippetRenderCtx,
+    now: SystemTime,
+) -> String {
+    let value = ctx
+        .variables
+        .get(name)
+        .cloned()
+        .or_else(|| derive_variable(name, ctx, now))
+        .unwrap_or_else(|| default.unwrap_or(name).to_string());
+
+    match regex {
+        Some(regex) => apply_regex_transform(&value, regex),
+        None => value,
+    }
+}
+
+fn apply_regex_transform(value: &str, regex: &Regex) -> String {
+    let mut builder = regex::RegexBuilder::new(regex.value);
+    if let Some(options) = regex.options {
+        builder.case_insensitive(options.contains('i'));
+    }
+    let Ok(re) = builder.build() else {
+        return value.to_string();
+    };
+
+    let replacer = |captures: &regex::Captures| -> String {
+        let mut replacement = String::new();
+        for item in &regex.replacement {
+            match item {
+                FormatItem::Text(text) => replacement.push_str(text),
+                FormatItem::Capture(n) => {
+                    if let Some(capture) = captures.get(*n) {
+                        replacement.push_str(capture.as_str());
+                    }
+                }
+                FormatItem::CaseChange(n, case) => {
+                    if let Some(capture) = captures.get(*n).map(|c| c.as_str()) {
+                        match case {
+                            CaseChange::Upcase => replacement.push_str(&capture.to_uppercase()),
+                            CaseChange::Downcase => {
+                                replacement.push_str(&capture.to_lowercase())
+                            }
+                            CaseChange::Capitalize => {
+                                let mut chars = capture.chars();
+                                if let Some(first) = chars.next() {
+                                    replacement.extend(first.to_uppercase());
+                                    replacement.push_str(chars.as_str());
+                                }
+                            }
+                        }
+                    }
+                }
+                FormatItem::Conditional(n, if_, else_) => {
+                    let matched = captures
+                        .get(*n)
+                        .map_or(false, |capture| !capture.as_str().is_empty());
+                    match (matched, if_, else_) {
+                        (true, Some(if_), _) => replacement.push_str(if_),
+                        (false, _, Some(else_)) => replacement.push_str(else_),
+                        _ => {}
+                    }
+                }
+            }
+        }
+        replacement
+    };
+
+    if regex.options.map_or(false, |options| options.contains('g')) {
+        re.replace_all(value, replacer).into_owned()
+    } else {
+        re.replace(value, replacer).into_owned()
+    }
+}
+
+/// Render a parsed [`Snippet`] into the text that should be inserted and the
+/// tabstops within that text, ordered for traversal: `$1`, `$2`, … and
+/// finally `$0`. Tabstops that occur more than once (mirrors, such as the
+/// two `${1:...}`s in `local ${1:var} = ${1:value}`) are collapsed into a
+/// single [`Tabstop`] with one range per occurrence. Variables (e.g.
+/// `TM_FILENAME`) are resolved through `ctx`. `ctx.now` (or [`SystemTime::now`]
+/// if unset) is read exactly once, so every `CURRENT_*` variable in the
+/// snippet reflects the same instant.
+pub fn render(snippet: &Snippet, ctx: &SnippetRenderCtx) -> RenderedSnippet {
+    let now = ctx.now.unwrap_or_else(SystemTime::now);
+    let mut state = RenderState::default();
+    for element in snippet {
+        state.push_element(element, ctx, now);
+    }
+
+    let mut tabstops: Vec<_> = state
+        .tabstops
+        .into_iter()
+        .map(|(tabstop, (ranges, choices))| Tabstop {
+            tabstop,
+            ranges,
+            choices,
+        })
+        .collect();
+    // `$0`, the final cursor position, is always visited last regardless of
+    // how it sorts numerically against the other tabstops.
+    tabstops.sort_by_key(|tabstop| (tabstop.tabstop == 0, tabstop.tabstop));
+
+    RenderedSnippet {
+        text: state.text,
+        tabstops,
+    }
+}
+
+pub use parser::parse;
+
+#[cfg(test)]
+mod render_test {
+    use super::*;
+
+    #[test]
+    fn render_text_only() {
+        let snippet = parse("hello world").unwrap();
+        let rendered = render(&snippet, &SnippetRenderCtx::default());
+        assert_eq!(rendered.text, "hello world");
+        assert!(rendered.tabstops.is_empty());
+    }
+
+    #[test]
+    fn render_placeholder_uses_default_text() {
+        let snippet = parse("match(${1:Arg1})").unwrap();
+        let rendered = render(&snippet, &SnippetRenderCtx::default());
+        assert_eq!(rendered.text, "match(Arg1)");
+        assert_eq!(rendered.tabstops.len(), 1);
+        assert_eq!(rendered.tabstops[0].tabstop, 1);
+        assert_eq!(rendered.tabstops[0].ranges, vec![6..10]);
+    }
+
+    #[test]
+    fn render_collapses_mirrored_tabstops() {
+        let snippet = parse("local ${1:var} = ${1:value}").unwrap();
+        let rendered = render(&snippet, &SnippetRenderCtx::default());
+        assert_eq!(rendered.text, "local var = value");
+        assert_eq!(rendered.tabstops.len(), 1);
+        assert_eq!(rendered.tabstops[0].tabstop, 1);
+        assert_eq!(rendered.tabstops[0].ranges, vec![6..9, 12..17]);
+    }
+
+    #[test]
+    fn render_choice_expands_to_first_option_and_keeps_alternatives() {
+        let snippet = parse("${1|one,two,three|}").unwrap();
+        let rendered = render(&snippet, &SnippetRenderCtx::default());
+        assert_eq!(rendered.text, "one");
+        assert_eq!(rendered.tabstops[0].tabstop, 1);
+        assert_eq!(rendered.tabstops[0].ranges, vec![0..3]);
+        assert_eq!(rendered.tabstops[0].choices, vec!["one", "two", "three"]);
+    }
+
+    #[test]
+    fn render_orders_tabstop_zero_last() {
+        let snippet = parse("$1 $0 $2").unwrap();
+        let rendered = render(&snippet, &SnippetRenderCtx::default());
+        let order: Vec<_> = rendered.tabstops.iter().map(|t| t.tabstop).collect();
+        assert_eq!(order, vec![1, 2, 0]);
+    }
+
+    #[test]
+    fn render_resolves_known_variable() {
+        let snippet = parse("$TM_FILENAME").unwrap();
+        let mut ctx = SnippetRenderCtx::default();
+        ctx.variables
+            .insert("TM_FILENAME".to_string(), "main.rs".to_string());
+        assert_eq!(render(&snippet, &ctx).text, "main.rs");
+    }
+
+    #[test]
+    fn render_falls_back_to_default_then_name() {
+        let snippet = parse("${UNKNOWN:fallback} $ALSO_UNKNOWN").unwrap();
+        let rendered = render(&snippet, &SnippetRenderCtx::default());
+        assert_eq!(rendered.text, "fallback ALSO_UNKNOWN");
+    }
+
+    #[test]
+    fn render_derives_filename_variables_from_path() {
+        let snippet =
+            parse("$TM_FILENAME $TM_FILENAME_BASE $TM_DIRECTORY $TM_FILEPATH").unwrap();
+        let ctx = SnippetRenderCtx {
+            filename: Some("/project/src/main.rs".into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            render(&snippet, &ctx).text,
+            "main.rs main /project/src /project/src/main.rs"
+        );
+    }
+
+    #[test]
+    fn render_derives_selection_clipboard_and_cursor() {
+        let snippet = parse("$TM_SELECTED_TEXT $CLIPBOARD $TM_LINE_NUMBER $TM_LINE_INDEX").unwrap();
+        let ctx = SnippetRenderCtx {
+            selection: Some("foo".to_string()),
+            clipboard: Some("bar".to_string()),
+            cursor: Some(CursorPosition { line: 4, column: 2 }),
+            ..Default::default()
+        };
+        assert_eq!(render(&snippet, &ctx).text, "foo bar 5 4");
+    }
+
+    #[test]
+    fn render_derives_current_datetime_from_pinned_clock() {
+        let snippet = parse(
+            "$CURRENT_YEAR-$CURRENT_MONTH-$CURRENT_DATE $CURRENT_HOUR:$CURRENT_MINUTE:$CURRENT_SECOND $CURRENT_DAY_NAME",
+        )
+        .unwrap();
+        // 2024-03-07T09:05:03Z, a Thursday.
+        let ctx = SnippetRenderCtx {
+            now: Some(UNIX_EPOCH + std::time::Duration::from_secs(1_709_802_303)),
+            ..Default::default()
+        };
+        assert_eq!(
+            render(&snippet, &ctx).text,
+            "2024-03-07 09:05:03 Thursday"
+        );
+    }
+
+    #[test]
+    fn render_variables_map_overrides_derived_value() {
+        let snippet = parse("$TM_FILENAME").unwrap();
+        let mut ctx = SnippetRenderCtx {
+            filename: Some("/project/src/main.rs".into()),
+            ..Default::default()
+        };
+        ctx.variables
+            .insert("TM_FILENAME".to_string(), "override.rs".to_string());
+        assert_eq!(render(&snippet, &ctx).text, "override.rs");
+    }
+
+    #[test]
+    fn render_applies_regex_transform() {
+        let snippet = parse("${TM_FILENAME/(.*)\\.rs/$1/}").unwrap();
+        let mut ctx = SnippetRenderCtx::default();
+        ctx.variables
+            .insert("TM_FILENAME".to_string(), "main.rs".to_string());
+        assert_eq!(render(&snippet, &ctx).text, "main");
+    }
+
+    #[test]
+    fn render_applies_global_regex_transform() {
+        let snippet = parse("${TM_SELECTED_TEXT/o/0/g}").unwrap();
+        let mut ctx = SnippetRenderCtx::default();
+        ctx.variables
+            .insert("TM_SELECTED_TEXT".to_string(), "foo bar foo".to_string());
+        assert_eq!(render(&snippet, &ctx).text, "f00 bar f00");
+    }
+
+    #[test]
+    fn render_nested_placeholder_registers_both_tabstops() {
+        let snippet = parse("${1:foo ${2:bar} baz}").unwrap();
+        let rendered = render(&snippet, &SnippetRenderCtx::default());
+        assert_eq!(rendered.text, "foo bar baz");
+
+        let outer = rendered.tabstops.iter().find(|t| t.tabstop == 1).unwrap();
+        assert_eq!(outer.ranges, vec![0..11]);
+
+        let inner = rendered.tabstops.iter().find(|t| t.tabstop == 2).unwrap();
+        assert_eq!(inner.ranges, vec![4..7]);
+    }
 }
 
-// TODO: remove this line once the parser is used.
-#[allow(dead_code)]
 mod parser {
+    use std::borrow::Cow;
+
     use once_cell::sync::Lazy;
 
     use crate::parser_combinator::*;
@@ -79,7 +576,85 @@ mod parser {
     static DIGIT: Lazy<regex::Regex> = Lazy::new(|| regex::Regex::new(r"^[0-9]+").unwrap());
     static VARIABLE: Lazy<regex::Regex> =
         Lazy::new(|| regex::Regex::new(r"^[_a-zA-Z][_a-zA-Z0-9]*").unwrap());
-    static TEXT: Lazy<regex::Regex> = Lazy::new(|| regex::Regex::new(r"^[^\$]+").unwrap());
+    // `\$`, `\}` and `\\` are recognized as escapes and unescaped by `text()`;
+    // any other backslash (e.g. `\d`, the `\t` in `C:\temp`) isn't a known
+    // escape and is left as a literal backslash by `unescape`. The third
+    // alternative matches such a backslash on its own so it doesn't get
+    // swallowed by the (non-matching) escape alternative.
+    static TEXT: Lazy<regex::Regex> =
+        Lazy::new(|| regex::Regex::new(r"^([^\\$]|\\[$}\\]|\\)+").unwrap());
+    // Like `TEXT`, but stops at an unescaped `,` or `|` (not at `$`, which is
+    // taken as literal text here) so `choice()` can split on those, while
+    // still allowing `\,` and `\|` through as literals.
+    static CHOICE_TEXT: Lazy<regex::Regex> =
+        Lazy::new(|| regex::Regex::new(r"^([^\\,|]|\\[$}\\,|]|\\)*").unwrap());
+
+    // The escapes recognized in ordinary snippet text: `\$`, `\}`, `\\`.
+    const TEXT_ESCAPES: &[char] = &['$', '}', '\\'];
+    // `choice()` additionally recognizes `\,` and `\|`, since those are its
+    // own item delimiters.
+    const CHOICE_ESCAPES: &[char] = &['$', '}', '\\', ',', '|'];
+
+    /// Unescape a `\x` pair into `x` for every `x` in `recognized`; any other
+    /// backslash (an undefined escape) is left untouched, backslash and all,
+    /// per the LSP snippet rule that unknown escapes are literal. Borrows
+    /// `raw` unchanged when it contains no backslash.
+    fn unescape<'a>(raw: &'a str, recognized: &[char]) -> Cow<'a, str> {
+        if !raw.contains('\\') {
+            return Cow::Borrowed(raw);
+        }
+
+        let mut unescaped = String::with_capacity(raw.len());
+        let mut chars = raw.chars().peekable();
+        while let Some(c) = chars.next() {
+            if c == '\\' {
+                if let Some(&escaped) = chars.peek() {
+                    if recognized.contains(&escaped) {
+                        unescaped.push(escaped);
+                        chars.next();
+                        continue;
+                    }
+                }
+            }
+            unescaped.push(c);
+        }
+        Cow::Owned(unescaped)
+    }
+
+    /// Matches a placeholder's default value: everything up to (but not
+    /// including) the `}` that balances the placeholder's own opening `${`.
+    /// Nested `${...}` regions (tabstops, variables, further placeholders)
+    /// have their braces counted so the first `}` they contain doesn't
+    /// prematurely end the outer placeholder, and a backslash-escaped `\}`
+    /// never counts towards the balance.
+    struct BalancedBraces;
+
+    impl<'a> Parser<'a> for BalancedBraces {
+        type Output = &'a str;
+
+        fn parse(&self, input: &'a str) -> Result<(&'a str, &'a str), &'a str> {
+            // Only `${` opens a nested region: a bare `{` in ordinary text
+            // (e.g. a placeholder default of `foo { bar`) doesn't need a
+            // matching `}` and must not throw off the brace count.
+            let mut depth = 0usize;
+            let mut chars = input.char_indices().peekable();
+            while let Some((i, c)) = chars.next() {
+                match c {
+                    '\\' => {
+                        chars.next();
+                    }
+                    '$' if matches!(chars.peek(), Some((_, '{'))) => {
+                        chars.next();
+                        depth += 1;
+                    }
+                    '}' if depth == 0 => return Ok((&input[i..], &input[..i])),
+                    '}' => depth -= 1,
+                    _ => {}
+                }
+            }
+            Err(input)
+        }
+    }
 
     fn var<'a>() -> impl Parser<'a, Output = &'a str> {
         pattern(&VARIABLE)
@@ -142,7 +717,7 @@ mod parser {
                 |seq| { Conditional(seq.1, None, Some(seq.4)) }
             ),
             // Any text
-            map(pattern(&TEXT), Text),
+            map(pattern(&TEXT), |s| Text(unescape(s, TEXT_ESCAPES))),
         )
     }
 
@@ -177,16 +752,14 @@ mod parser {
     }
 
     fn placeholder<'a>() -> impl Parser<'a, Output = SnippetElement<'a>> {
-        // TODO: why doesn't parse_as work?
-        // let value = reparse_as(take_until(|c| c == '}'), anything());
-        let value = filter_map(take_until(|c| c == '}'), |s| {
-            anything().parse(s).map(|parse_result| parse_result.1).ok()
+        let value = filter_map(BalancedBraces, |s| {
+            snippet().parse(s).map(|parse_result| parse_result.1).ok()
         });
 
         map(seq!("${", digit(), ":", value, "}"), |seq| {
             SnippetElement::Placeholder {
                 tabstop: seq.1,
-                value: Box::new(seq.3),
+                value: seq.3,
             }
         })
     }
@@ -197,7 +770,7 @@ mod parser {
                 "${",
                 digit(),
                 "|",
-                sep(take_until(|c| c == ',' || c == '|'), ","),
+                sep(map(pattern(&CHOICE_TEXT), |s| unescape(s, CHOICE_ESCAPES)), ","),
                 "|}",
             ),
             |seq| SnippetElement::Choice {
@@ -236,7 +809,7 @@ mod parser {
     }
 
     fn text<'a>() -> impl Parser<'a, Output = SnippetElement<'a>> {
-        map(pattern(&TEXT), SnippetElement::Text)
+        map(pattern(&TEXT), |s| SnippetElement::Text(unescape(s, TEXT_ESCAPES)))
     }
 
     fn anything<'a>() -> impl Parser<'a, Output = SnippetElement<'a>> {
@@ -265,12 +838,12 @@ mod parser {
         fn parse_placeholders_in_function_call() {
             assert_eq!(
                 Ok(vec![
-                    Text("match("),
+                    Text("match(".into()),
                     Placeholder {
                         tabstop: 1,
-                        value: Box::new(Text("Arg1")),
+                        value: vec![Text("Arg1".into())],
                     },
-                    Text(")")
+                    Text(")".into())
                 ]),
                 parse("match(${1:Arg1})")
             )
@@ -280,15 +853,15 @@ mod parser {
         fn parse_placeholders_in_statement() {
             assert_eq!(
                 Ok(vec![
-                    Text("local "),
+                    Text("local ".into()),
                     Placeholder {
                         tabstop: 1,
-                        value: Box::new(Text("var")),
+                        value: vec![Text("var".into())],
                     },
-                    Text(" = "),
+                    Text(" = ".into()),
                     Placeholder {
                         tabstop: 1,
-                        value: Box::new(Text("value")),
+                        value: vec![Text("value".into())],
                     },
                 ]),
                 parse("local ${1:var} = ${1:value}")
@@ -299,27 +872,27 @@ mod parser {
         fn parse_all() {
             assert_eq!(
                 Ok(vec![
-                    Text("hello "),
+                    Text("hello ".into()),
                     Tabstop { tabstop: 1 },
                     Tabstop { tabstop: 2 },
-                    Text(" "),
+                    Text(" ".into()),
                     Choice {
                         tabstop: 1,
-                        choices: vec!["one", "two", "three"]
+                        choices: vec!["one".into(), "two".into(), "three".into()]
                     },
-                    Text(" "),
+                    Text(" ".into()),
                     Variable {
                         name: "name",
                         default: Some("foo"),
                         regex: None
                     },
-                    Text(" "),
+                    Text(" ".into()),
                     Variable {
                         name: "var",
                         default: None,
                         regex: None
                     },
-                    Text(" "),
+                    Text(" ".into()),
                     Variable {
                         name: "TM",
                         default: None,
@@ -345,5 +918,91 @@ mod parser {
                 parse("${TM_FILENAME/(.*).+$/$1/}")
             );
         }
+
+        #[test]
+        fn escaped_dollar_is_not_a_tabstop() {
+            assert_eq!(
+                Ok(vec![Text("price: ${1:0.00}".into())]),
+                parse(r"price: \${1:0.00}")
+            );
+        }
+
+        #[test]
+        fn escaped_brace_and_backslash_in_text() {
+            assert_eq!(
+                Ok(vec![Text(r"a } b \ c".into())]),
+                parse(r"a \} b \\ c")
+            );
+        }
+
+        #[test]
+        fn escaped_comma_and_pipe_in_choice() {
+            assert_eq!(
+                Ok(vec![Choice {
+                    tabstop: 1,
+                    choices: vec!["a, b".into(), "c | d".into()],
+                }]),
+                parse(r"${1|a\, b,c \| d|}")
+            );
+        }
+
+        #[test]
+        fn undefined_escape_in_text_keeps_its_backslash() {
+            assert_eq!(Ok(vec![Text(r"\d".into())]), parse(r"\d"));
+            assert_eq!(Ok(vec![Text(r"C:\temp".into())]), parse(r"C:\temp"));
+        }
+
+        #[test]
+        fn undefined_escape_in_choice_keeps_its_backslash() {
+            assert_eq!(
+                Ok(vec![Choice {
+                    tabstop: 1,
+                    choices: vec![r"\d".into(), "b".into()],
+                }]),
+                parse(r"${1|\d,b|}")
+            );
+        }
+
+        #[test]
+        fn placeholder_with_multiple_nested_elements() {
+            assert_eq!(
+                Ok(vec![Placeholder {
+                    tabstop: 1,
+                    value: vec![Text("foo ".into()), Tabstop { tabstop: 2 }],
+                }]),
+                parse("${1:foo $2}")
+            );
+        }
+
+        #[test]
+        fn placeholder_with_recursively_nested_placeholder() {
+            assert_eq!(
+                Ok(vec![Placeholder {
+                    tabstop: 1,
+                    value: vec![
+                        Text("foo ".into()),
+                        Placeholder {
+                            tabstop: 2,
+                            value: vec![Text("bar".into())],
+                        },
+                        Text(" baz".into()),
+                    ],
+                }]),
+                parse("${1:foo ${2:bar} baz}")
+            );
+        }
+
+        #[test]
+        fn placeholder_default_with_bare_unbalanced_brace() {
+            // A lone `{` that isn't part of `${...}` syntax doesn't open a
+            // nested region, so it shouldn't need a matching `}` either.
+            assert_eq!(
+                Ok(vec![Placeholder {
+                    tabstop: 1,
+                    value: vec![Text("foo { bar".into())],
+                }]),
+                parse("${1:foo { bar}")
+            );
+        }
     }
 }
\ No newline at end of file